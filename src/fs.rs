@@ -0,0 +1,215 @@
+// Copyright © 2023 Vouch.io LLC
+
+use anyhow::{bail, Error, Result};
+use log::debug;
+use log::info;
+use serde_cbor;
+use serde_cbor::Value;
+use serde_json;
+use std::collections::BTreeMap;
+use std::fs::{read, File};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::cli::*;
+use crate::nmp_hdr::*;
+use crate::transfer::check_rc;
+use crate::transfer::encode_request;
+use crate::transfer::next_seq_id;
+use crate::transfer::framed_len;
+use crate::transfer::transceive;
+
+// extract an unsigned integer field from a response map, if present
+fn get_uint(body: &Value, name: &str) -> Option<u64> {
+    if let Value::Map(object) = body {
+        for (key, val) in object.iter() {
+            if let (Value::Text(k), Value::Integer(v)) = (key, val) {
+                if k == name {
+                    return Some(*v as u64);
+                }
+            }
+        }
+    }
+    None
+}
+
+pub fn fs_upload(cli: &Cli, local: &PathBuf, remote_path: &str) -> Result<(), Error> {
+    info!(
+        "upload file {} to {}",
+        local.to_string_lossy(),
+        remote_path
+    );
+
+    // load file
+    let data = read(local)?;
+    info!("{} bytes to transfer", data.len());
+
+    // transfer in blocks
+    let mut off: usize = 0;
+    loop {
+        let off_start = off;
+        let mut try_length = cli.mtu;
+        debug!("try_length: {}", try_length);
+        let seq_id = next_seq_id();
+        loop {
+            if off + try_length > data.len() {
+                try_length = data.len() - off;
+            }
+            let chunk = data[off..off + try_length].to_vec();
+
+            // build the fs upload map; len is only sent with the first chunk
+            let mut map: BTreeMap<Value, Value> = BTreeMap::new();
+            map.insert(Value::Text("name".into()), Value::Text(remote_path.into()));
+            map.insert(Value::Text("off".into()), Value::Integer(off as i128));
+            if off == 0 {
+                map.insert(Value::Text("len".into()), Value::Integer(data.len() as i128));
+            }
+            map.insert(Value::Text("data".into()), Value::Bytes(chunk));
+            let body = serde_cbor::to_vec(&Value::Map(map))?;
+
+            let (packet, request_header) = encode_request(
+                cli,
+                NmpOp::Write,
+                NmpGroup::FS,
+                NmpIdFs::File,
+                &body,
+                seq_id,
+            )?;
+
+            // test if the transport framing would exceed the MTU
+            let framed = framed_len(cli, &packet)?;
+            if framed > cli.mtu {
+                let reduce = framed - cli.mtu;
+                if reduce > try_length {
+                    bail!("MTU too small");
+                }
+
+                // with serial framing the number of bytes to reduce is base64 encoded, calculate
+                // back the number of bytes and then reduce a bit more for base64 filling and
+                // rounding; with UDP the excess is raw bytes and the loop shrinks further until
+                // the packet fits
+                try_length -= reduce * 3 / 4 + 3;
+                debug!("new try_length: {}", try_length);
+                continue;
+            }
+
+            // send request
+            let (response_header, response_body) = transceive(cli, packet)?;
+
+            // verify sequence id
+            if response_header.seq != request_header.seq {
+                bail!("wrong sequence number");
+            }
+
+            // verify response
+            if response_header.op != NmpOp::WriteRsp || response_header.group != NmpGroup::FS {
+                bail!("wrong response types");
+            }
+
+            // verify result code and update offset from the returned off field
+            debug!(
+                "response_body: {}",
+                serde_json::to_string_pretty(&response_body)?
+            );
+            check_rc(&response_body)?;
+            if let Some(new_off) = get_uint(&response_body, "off") {
+                off = new_off as usize;
+            }
+
+            break;
+        }
+
+        // an empty file is a single zero-length write: the device echoes off 0
+        // and there is nothing left to transfer, so complete before treating a
+        // non-advancing offset as an error
+        if off == data.len() {
+            info!("100% uploaded");
+            break;
+        }
+
+        // otherwise the device must have advanced the offset; a repeated offset
+        // means we would resend the same chunk forever
+        if off_start == off {
+            bail!("wrong offset received");
+        }
+        info!("{}% uploaded", 100 * off / data.len());
+    }
+    info!("upload complete");
+    Ok(())
+}
+
+pub fn fs_download(cli: &Cli, remote_path: &str, local: &PathBuf) -> Result<(), Error> {
+    info!(
+        "download file {} to {}",
+        remote_path,
+        local.to_string_lossy()
+    );
+
+    let mut file = File::create(local)?;
+    let mut off: u64 = 0;
+    let mut total: Option<u64> = None;
+    loop {
+        let mut map: BTreeMap<Value, Value> = BTreeMap::new();
+        map.insert(Value::Text("name".into()), Value::Text(remote_path.into()));
+        map.insert(Value::Text("off".into()), Value::Integer(off as i128));
+        let body = serde_cbor::to_vec(&Value::Map(map))?;
+
+        let (packet, request_header) = encode_request(
+            cli,
+            NmpOp::Read,
+            NmpGroup::FS,
+            NmpIdFs::File,
+            &body,
+            next_seq_id(),
+        )?;
+        let (response_header, response_body) = transceive(cli, packet)?;
+
+        // verify sequence id
+        if response_header.seq != request_header.seq {
+            bail!("wrong sequence number");
+        }
+
+        // verify response
+        if response_header.op != NmpOp::ReadRsp || response_header.group != NmpGroup::FS {
+            bail!("wrong response types");
+        }
+
+        check_rc(&response_body)?;
+
+        // the total length is only sent with the first response
+        if total.is_none() {
+            total = get_uint(&response_body, "len");
+        }
+
+        // append the returned data to the local file
+        let mut got_data = false;
+        if let Value::Map(object) = &response_body {
+            for (key, val) in object.iter() {
+                if let (Value::Text(k), Value::Bytes(bytes)) = (key, val) {
+                    if k == "data" {
+                        file.write_all(bytes)?;
+                        off += bytes.len() as u64;
+                        got_data = true;
+                    }
+                }
+            }
+        }
+
+        match total {
+            Some(len) => {
+                info!("{}% downloaded", 100 * off / len.max(1));
+                if off >= len {
+                    break;
+                }
+                // a response that carries no data while more remains would make
+                // us re-request the same offset forever; fail instead of looping
+                if !got_data {
+                    bail!("no data received at offset {}", off);
+                }
+            }
+            None => bail!("no length received"),
+        }
+    }
+    info!("download complete");
+    Ok(())
+}
@@ -4,15 +4,19 @@ use anyhow::{bail, Error, Result};
 use log::debug;
 use log::info;
 use serde_cbor;
+use serde_cbor::Value;
 use serde_json;
 use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::fs::read;
 use std::path::PathBuf;
 
 use crate::cli::*;
 use crate::nmp_hdr::*;
+use crate::transfer::check_rc;
 use crate::transfer::encode_request;
 use crate::transfer::next_seq_id;
+use crate::transfer::framed_len;
 use crate::transfer::transceive;
 
 pub fn list(cli: &Cli) -> Result<(), Error> {
@@ -41,6 +45,10 @@ pub fn list(cli: &Cli) -> Result<(), Error> {
         bail!("wrong response types");
     }
 
+    // a v2 device can answer a list request with an `err` map (and a legacy one
+    // with a flat non-zero `rc`); surface that instead of printing it as success
+    check_rc(&response_body)?;
+
     // print body
     info!(
         "response: {}",
@@ -50,6 +58,85 @@ pub fn list(cli: &Cli) -> Result<(), Error> {
     Ok(())
 }
 
+// write the image state map, optionally marking an image for swap on the
+// next boot (confirm == false) or making it permanent (confirm == true)
+fn set_state(cli: &Cli, hash: Option<&[u8]>, confirm: bool) -> Result<(), Error> {
+    let mut map: BTreeMap<Value, Value> = BTreeMap::new();
+    if let Some(hash) = hash {
+        map.insert(Value::Text("hash".into()), Value::Bytes(hash.to_vec()));
+    }
+    map.insert(Value::Text("confirm".into()), Value::Bool(confirm));
+    let body = serde_cbor::to_vec(&Value::Map(map))?;
+
+    let (data, request_header) = encode_request(
+        cli,
+        NmpOp::Write,
+        NmpGroup::Image,
+        NmpIdImage::State,
+        &body,
+        next_seq_id(),
+    )?;
+    let (response_header, response_body) = transceive(cli, data)?;
+
+    // verify sequence id
+    if response_header.seq != request_header.seq {
+        bail!("wrong sequence number");
+    }
+
+    // verify response
+    if response_header.op != NmpOp::WriteRsp || response_header.group != NmpGroup::Image {
+        bail!("wrong response types");
+    }
+
+    check_rc(&response_body)?;
+    info!(
+        "response: {}",
+        serde_json::to_string_pretty(&response_body)?
+    );
+    Ok(())
+}
+
+pub fn test(cli: &Cli, hash: &[u8]) -> Result<(), Error> {
+    info!("send image test request");
+    set_state(cli, Some(hash), false)
+}
+
+pub fn confirm(cli: &Cli, hash: Option<&[u8]>) -> Result<(), Error> {
+    info!("send image confirm request");
+    set_state(cli, hash, true)
+}
+
+pub fn erase(cli: &Cli, slot: u32) -> Result<(), Error> {
+    info!("send image erase request for slot {}", slot);
+
+    let mut map: BTreeMap<Value, Value> = BTreeMap::new();
+    map.insert(Value::Text("slot".into()), Value::Integer(slot as i128));
+    let body = serde_cbor::to_vec(&Value::Map(map))?;
+
+    let (data, request_header) = encode_request(
+        cli,
+        NmpOp::Write,
+        NmpGroup::Image,
+        NmpIdImage::Erase,
+        &body,
+        next_seq_id(),
+    )?;
+    let (response_header, response_body) = transceive(cli, data)?;
+
+    // verify sequence id
+    if response_header.seq != request_header.seq {
+        bail!("wrong sequence number");
+    }
+
+    // verify response
+    if response_header.op != NmpOp::WriteRsp || response_header.group != NmpGroup::Image {
+        bail!("wrong response types");
+    }
+
+    check_rc(&response_body)?;
+    Ok(())
+}
+
 pub fn upload(cli: &Cli, filename: &PathBuf) -> Result<(), Error> {
     info!("upload file: {}", filename.to_string_lossy());
 
@@ -95,7 +182,7 @@ pub fn upload(cli: &Cli, filename: &PathBuf) -> Result<(), Error> {
 
             // convert to bytes with CBOR
             let body = serde_cbor::to_vec(&req)?;
-            let (chunk, request_header) = encode_request(
+            let (packet, request_header) = encode_request(
                 cli,
                 NmpOp::Write,
                 NmpGroup::Image,
@@ -104,9 +191,10 @@ pub fn upload(cli: &Cli, filename: &PathBuf) -> Result<(), Error> {
                 seq_id,
             )?;
 
-            // test if too long
-            if chunk.len() > cli.mtu {
-                let reduce = chunk.len() - cli.mtu;
+            // test if the transport framing would exceed the MTU
+            let framed = framed_len(cli, &packet)?;
+            if framed > cli.mtu {
+                let reduce = framed - cli.mtu;
                 if reduce > try_length {
                     bail!("MTU too small");
                 }
@@ -119,7 +207,7 @@ pub fn upload(cli: &Cli, filename: &PathBuf) -> Result<(), Error> {
             }
 
             // send request
-            let (response_header, response_body) = transceive(cli, chunk)?;
+            let (response_header, response_body) = transceive(cli, packet)?;
 
             // verify sequence id
             if response_header.seq != request_header.seq {
@@ -136,22 +224,15 @@ pub fn upload(cli: &Cli, filename: &PathBuf) -> Result<(), Error> {
                 "response_body: {}",
                 serde_json::to_string_pretty(&response_body)?
             );
-            if let serde_cbor::Value::Map(object) = response_body {
+            check_rc(&response_body)?;
+            if let serde_cbor::Value::Map(object) = &response_body {
                 for (key, val) in object.iter() {
-                    match key {
-                        serde_cbor::Value::Text(rc_key) if rc_key == "rc" => {
-                            if let serde_cbor::Value::Integer(rc) = val {
-                                if *rc != 0 {
-                                    bail!("rc = {}", rc);
-                                }
-                            }
-                        }
-                        serde_cbor::Value::Text(off_key) if off_key == "off" => {
+                    if let serde_cbor::Value::Text(off_key) = key {
+                        if off_key == "off" {
                             if let serde_cbor::Value::Integer(off_val) = val {
                                 off = *off_val as usize;
                             }
                         }
-                        _ => (),
                     }
                 }
             }
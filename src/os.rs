@@ -0,0 +1,42 @@
+// Copyright © 2023 Vouch.io LLC
+
+use anyhow::{bail, Error, Result};
+use log::info;
+use serde_cbor;
+use std::collections::BTreeMap;
+
+use crate::cli::*;
+use crate::nmp_hdr::*;
+use crate::transfer::check_rc;
+use crate::transfer::encode_request;
+use crate::transfer::next_seq_id;
+use crate::transfer::transceive;
+
+pub fn reset(cli: &Cli) -> Result<(), Error> {
+    info!("send os reset request");
+
+    // the reset command carries an empty map as its body
+    let body = serde_cbor::to_vec(&BTreeMap::<String, String>::new())?;
+    let (data, request_header) = encode_request(
+        cli,
+        NmpOp::Write,
+        NmpGroup::OS,
+        NmpIdOs::Reset,
+        &body,
+        next_seq_id(),
+    )?;
+    let (response_header, response_body) = transceive(cli, data)?;
+
+    // verify sequence id
+    if response_header.seq != request_header.seq {
+        bail!("wrong sequence number");
+    }
+
+    // verify response
+    if response_header.op != NmpOp::WriteRsp || response_header.group != NmpGroup::OS {
+        bail!("wrong response types");
+    }
+
+    check_rc(&response_body)?;
+    Ok(())
+}
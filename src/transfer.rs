@@ -6,30 +6,30 @@ use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use crc16::*;
 use hex;
 use lazy_static::lazy_static;
-use log::debug;
+use log::{debug, warn};
 use rand::{thread_rng, Rng};
 use serde_cbor;
 use serialport::SerialPort;
 use std::cmp::min;
-use std::io::Cursor;
+use std::io::{Cursor, Read};
+use std::net::UdpSocket;
 use std::sync::atomic::{AtomicU8, Ordering};
 use std::time::Duration;
 
 use crate::cli::*;
 use crate::nmp_hdr::*;
 
-fn read_byte(port: &mut dyn SerialPort) -> Result<u8, Error> {
-    let mut byte = [0u8];
-    port.read(&mut byte)?;
-    Ok(byte[0])
-}
+// how many leading bytes of noise to skip while looking for a start marker
+const START_MARKER_BUDGET: usize = 1024;
 
-fn expect_byte(port: &mut dyn SerialPort, b: u8) -> Result<(), Error> {
-    let read = read_byte(port)?;
-    if read != b {
-        bail!("read error, expected: {}, read: {}", b, read);
+fn read_byte<R: Read + ?Sized>(port: &mut R) -> Result<u8, Error> {
+    let mut byte = [0u8];
+    // a zero-length read means the stream ended before the frame did; treat it
+    // as an error rather than spinning forever on phantom 0 bytes
+    if port.read(&mut byte)? == 0 {
+        bail!("unexpected end of input");
     }
-    Ok(())
+    Ok(byte[0])
 }
 
 fn write_byte(port: &mut dyn SerialPort, b: u8) -> Result<(), Error> {
@@ -46,22 +46,66 @@ pub fn next_seq_id() -> u8 {
     COUNTER.fetch_add(1, Ordering::SeqCst)
 }
 
-pub fn encode_request(
+pub fn encode_request<I: Into<u8>>(
     cli: &Cli,
     op: NmpOp,
     group: NmpGroup,
-    id: NmpIdImage,
+    id: I,
     body: &Vec<u8>,
     seq_id: u8,
 ) -> Result<(Vec<u8>, NmpHdr), Error> {
     // create request
-    let mut request_header = NmpHdr::new_req(op, group, id);
+    let mut request_header = NmpHdr::new_req(op, group, id.into());
     request_header.seq = seq_id;
     request_header.len = body.len() as u16;
+    // select SMP protocol version: serialize() encodes the version bits in the
+    // flags byte, so v2 devices answer with the structured err result group
+    request_header.version = cli.smp_version;
     debug!("request header: {:?}", request_header);
-    let mut serialized = request_header.serialize()?;
-    serialized.extend(body);
-    debug!("serialized: {}", hex::encode(&serialized));
+    let mut packet = request_header.serialize()?;
+    packet.extend(body);
+    debug!("serialized: {}", hex::encode(&packet));
+
+    // the returned packet is just the SMP header plus CBOR body; any
+    // transport-specific framing is applied by the Transport implementation
+    Ok((packet, request_header))
+}
+
+/// A link over which a single SMP packet (header + CBOR body) is exchanged.
+///
+/// Implementations own whatever framing the link requires: the serial
+/// console variant adds a length prefix, CRC16, base64 and line markers,
+/// whereas the UDP variant sends the packet as a raw datagram.
+pub trait Transport {
+    /// Send one request packet, applying the transport's framing.
+    fn send(&mut self, frame: &[u8]) -> Result<(), Error>;
+    /// Receive one response packet with the framing stripped.
+    fn recv(&mut self) -> Result<Vec<u8>, Error>;
+}
+
+/// SMP over the serial console: length prefix, CRC16, base64 and the
+/// `6 9` / `4 20` line markers of the newtmgr line protocol.
+pub struct SerialTransport {
+    port: Box<dyn SerialPort>,
+    linelength: usize,
+}
+
+impl SerialTransport {
+    pub fn open(cli: &Cli) -> Result<SerialTransport, Error> {
+        let port = serialport::new(&cli.device, cli.baudrate)
+            .timeout(Duration::from_secs(cli.timeout as u64))
+            .open()?;
+        Ok(SerialTransport {
+            port,
+            linelength: cli.linelength,
+        })
+    }
+}
+
+/// Apply the serial console framing to a packet: CRC16, length prefix, base64
+/// and the `6 9` / `4 20` line markers, split into `linelength`-sized lines.
+pub fn serial_frame(linelength: usize, frame: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut serialized = frame.to_vec();
 
     // calculate CRC16 of it and append to the request
     let checksum = State::<XMODEM>::calculate(&serialized);
@@ -93,54 +137,120 @@ pub fn encode_request(
             // thread::sleep(Duration::from_millis(20));
             data.extend_from_slice(&[4, 20]);
         }
-        let write_len = min(cli.linelength - 4, totlen - written);
+        let write_len = min(linelength - 4, totlen - written);
         data.extend_from_slice(&base64_data[written..written + write_len]);
         data.push(b'\n');
         written += write_len;
     }
-
-    Ok((data, request_header))
+    Ok(data)
 }
 
-pub fn transceive(cli: &Cli, data: Vec<u8>) -> Result<(NmpHdr, serde_cbor::Value), Error> {
-    // open serial port
-    let mut port = serialport::new(&cli.device, cli.baudrate)
-        .timeout(Duration::from_secs(cli.timeout as u64))
-        .open()?;
+impl Transport for SerialTransport {
+    fn send(&mut self, frame: &[u8]) -> Result<(), Error> {
+        // empty input buffer
+        let to_read = self.port.bytes_to_read()?;
+        for _ in 0..to_read {
+            read_byte(&mut *self.port)?;
+        }
 
-    // empty input buffer
-    let to_read = port.bytes_to_read()?;
-    for _ in 0..to_read {
-        read_byte(&mut *port)?;
+        // write request
+        for b in serial_frame(self.linelength, frame)? {
+            write_byte(&mut *self.port, b)?;
+        }
+        Ok(())
     }
 
-    // write request
-    for b in data {
-        write_byte(&mut *port, b)?;
+    fn recv(&mut self) -> Result<Vec<u8>, Error> {
+        read_frame(&mut *self.port)
     }
+}
 
-    // read result
-
-    // first wait for the chunk start marker
-    expect_byte(&mut *port, 6)?;
-    expect_byte(&mut *port, 9)?;
-
-    // next read until newline
-    let mut result: Vec<u8> = Vec::new();
+/// Read and unframe one serial console frame from `port`.
+///
+/// Leading noise (boot banners, log text, a glitched byte) is skipped while
+/// scanning for the `6 9` start marker, up to a bounded budget. The frame may
+/// span several lines: the first line follows the `6 9` marker, each
+/// continuation line follows a `4 20` marker, and a `6 9` seen mid-frame means
+/// the device resynced and the partial frame is dropped. Because a device is
+/// free to split the base64 stream on any byte boundary, the base64 *text* is
+/// concatenated across lines and decoded in 4-character groups rather than
+/// per line, which would break whenever a line length is not a multiple of 4.
+fn read_frame<R: Read + ?Sized>(port: &mut R) -> Result<Vec<u8>, Error> {
+    // scan for the 6 9 start marker, skipping any leading noise up to a bounded
+    // budget rather than failing on the first unexpected byte
+    let mut budget = START_MARKER_BUDGET;
+    let mut last: Option<u8> = None;
     loop {
-        let b = read_byte(&mut *port)?;
-        if b == 0xa {
+        if budget == 0 {
+            bail!("no start marker found within {} bytes", START_MARKER_BUDGET);
+        }
+        let b = read_byte(port)?;
+        budget -= 1;
+        if last == Some(6) && b == 9 {
             break;
-        } else {
-            result.push(b);
         }
+        if b != 6 {
+            debug!("skipping leading byte: {}", b);
+        }
+        last = Some(b);
     }
 
-    // TODO: could be more lines, need to check if length is equal to packet length
+    // reassemble a possibly multi-line frame. The base64 text is accumulated in
+    // `pending` and decoded only in whole 4-character groups; any trailing bytes
+    // of an unaligned line are carried over to the next one. The decoded bytes
+    // grow in `decoded` until the 16-bit big-endian length prefix at the front
+    // has been reached.
+    let mut decoded: Vec<u8> = Vec::new();
+    let mut pending: Vec<u8> = Vec::new();
+    let mut expected: Option<usize> = None;
+    loop {
+        // read one base64 encoded line up to the newline
+        let mut line: Vec<u8> = Vec::new();
+        loop {
+            let b = read_byte(port)?;
+            if b == 0xa {
+                break;
+            } else {
+                line.push(b);
+            }
+        }
+        debug!("result line: {}", String::from_utf8(line.clone())?);
+
+        // decode the complete 4-character groups we now have, keeping any
+        // remainder for the next line so a split mid-group still decodes
+        pending.extend_from_slice(&line);
+        let aligned = pending.len() - pending.len() % 4;
+        decoded.extend_from_slice(&general_purpose::STANDARD.decode(&pending[..aligned])?);
+        pending.drain(..aligned);
 
-    // decode base64
-    debug!("result string: {}", String::from_utf8(result.clone())?);
-    let decoded: Vec<u8> = general_purpose::STANDARD.decode(&result)?;
+        // the total length is the 2-byte prefix at the very front of the frame
+        if expected.is_none() {
+            if decoded.len() < 2 {
+                bail!("frame too short");
+            }
+            expected = Some(BigEndian::read_u16(&decoded) as usize);
+        }
+
+        // stop once the declared length (excluding the 2-byte prefix) is reached
+        if decoded.len() - 2 >= expected.unwrap() {
+            break;
+        }
+
+        // otherwise a continuation marker must precede the next line's payload;
+        // a start marker in the middle of a frame means the device resynced
+        let m0 = read_byte(port)?;
+        let m1 = read_byte(port)?;
+        match (m0, m1) {
+            (4, 20) => (),
+            (6, 9) => {
+                debug!("start marker inside frame, resyncing");
+                decoded.clear();
+                pending.clear();
+                expected = None;
+            }
+            _ => bail!("unexpected marker: {} {}", m0, m1),
+        }
+    }
 
     // verify length: must be the decoded length, minus the 2 bytes to encode the length
     let len = BigEndian::read_u16(&decoded) as usize;
@@ -156,23 +266,290 @@ pub fn transceive(cli: &Cli, data: Vec<u8>) -> Result<(NmpHdr, serde_cbor::Value
         bail!("wrong checksum");
     }
 
-    // read header
-    let mut cursor = Cursor::new(&data);
-    let response_header = NmpHdr::deserialize(&mut cursor).unwrap();
-    debug!("response header: {:?}", response_header);
+    Ok(data)
+}
+
+/// SMP over UDP: the header and CBOR body travel as a raw datagram with no
+/// length prefix, CRC16, base64 or line markers.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    mtu: usize,
+}
+
+impl UdpTransport {
+    pub fn open(cli: &Cli, addr: &str) -> Result<UdpTransport, Error> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_read_timeout(Some(Duration::from_secs(cli.timeout as u64)))?;
+        socket.connect(addr)?;
+        Ok(UdpTransport {
+            socket,
+            mtu: cli.mtu,
+        })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.socket.send(frame)?;
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>, Error> {
+        // size the buffer generously so multi-slot image lists and larger fs
+        // reads arrive whole; if a datagram still fills the buffer completely we
+        // cannot tell whether it was truncated, so treat that as an error rather
+        // than silently returning a partial body that fails CBOR decode
+        let mut buf = vec![0u8; self.mtu.max(2048)];
+        let n = self.socket.recv(&mut buf)?;
+        if n == buf.len() {
+            bail!("datagram too large for {} byte buffer", buf.len());
+        }
+        buf.truncate(n);
+        Ok(buf)
+    }
+}
+
+// true when `--connection udp:...` selects the datagram transport
+fn is_udp(cli: &Cli) -> bool {
+    matches!(&cli.connection, Some(conn) if conn.starts_with("udp:"))
+}
+
+/// Build the transport selected by the `--connection` option: a `udp:host:port`
+/// string picks UDP, anything else (or the absence of the option) falls back to
+/// the serial port named by `--device`.
+pub fn open_transport(cli: &Cli) -> Result<Box<dyn Transport>, Error> {
+    match &cli.connection {
+        Some(conn) if conn.starts_with("udp:") => {
+            Ok(Box::new(UdpTransport::open(cli, &conn[4..])?))
+        }
+        _ => Ok(Box::new(SerialTransport::open(cli)?)),
+    }
+}
+
+/// Wire size `packet` will occupy once the configured transport's framing is
+/// applied, so chunked uploads can be sized against the MTU without assuming
+/// serial framing: the serial console expands the packet with a length prefix,
+/// CRC16, base64 and line markers, whereas UDP sends it verbatim.
+pub fn framed_len(cli: &Cli, packet: &[u8]) -> Result<usize, Error> {
+    if is_udp(cli) {
+        Ok(packet.len())
+    } else {
+        Ok(serial_frame(cli.linelength, packet)?.len())
+    }
+}
 
+/// Inspect an SMP response body for an error and turn it into a `Result`.
+///
+/// Both the legacy flat `{ "rc": n }` field and the SMP v2 structured
+/// `{ "err": { "group": g, "rc": r } }` map are understood; a non-zero flat
+/// `rc` is reported as `rc = R`, a non-zero `rc` in an `err` map as
+/// `error: group G rc R`.
+pub fn check_rc(body: &serde_cbor::Value) -> Result<(), Error> {
+    if let serde_cbor::Value::Map(object) = body {
+        for (key, val) in object.iter() {
+            let serde_cbor::Value::Text(key) = key else {
+                continue;
+            };
+            match key.as_str() {
+                "rc" => {
+                    if let serde_cbor::Value::Integer(rc) = val {
+                        if *rc != 0 {
+                            bail!("rc = {}", rc);
+                        }
+                    }
+                }
+                "err" => {
+                    if let serde_cbor::Value::Map(err) = val {
+                        let mut group = 0;
+                        let mut rc: Option<i128> = None;
+                        for (k, v) in err.iter() {
+                            if let (serde_cbor::Value::Text(k), serde_cbor::Value::Integer(v)) =
+                                (k, v)
+                            {
+                                match k.as_str() {
+                                    "group" => group = *v,
+                                    "rc" => rc = Some(*v),
+                                    _ => (),
+                                }
+                            }
+                        }
+                        // an err map is only fatal when it actually carries a
+                        // non-zero rc; a present-but-zero rc (or no rc key at
+                        // all) is a success, not `group 0 rc 0`
+                        if let Some(rc) = rc {
+                            if rc != 0 {
+                                bail!("error: group {} rc {}", group, rc);
+                            }
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+// decode a single response packet: the device is untrusted, so a short or
+// malformed buffer must return an error instead of panicking
+fn decode_response(data: &[u8]) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+    if data.len() < 8 {
+        bail!("response too short: {} bytes", data.len());
+    }
+    let mut cursor = Cursor::new(data);
+    let response_header = NmpHdr::deserialize(&mut cursor)?;
+    debug!("response header: {:?}", response_header);
     debug!("cbor: {}", hex::encode(&data[8..]));
 
     // decode body in CBOR format
     let body = serde_cbor::from_reader(cursor)?;
-
     Ok((response_header, body))
 }
 
+/// Send one request packet over the configured transport and decode the
+/// response header and CBOR body.
+///
+/// The device is treated as untrusted: a timeout, CRC mismatch, malformed
+/// header or wrong sequence number re-sends the same frame (and thus the same
+/// `seq_id`) up to `cli.retries` times before giving up.
+pub fn transceive(cli: &Cli, data: Vec<u8>) -> Result<(NmpHdr, serde_cbor::Value), Error> {
+    let mut transport = open_transport(cli)?;
+
+    // the request sequence number is part of the serialized header; a response
+    // carrying a different one means we lost sync and should retry
+    let expected_seq = NmpHdr::deserialize(&mut Cursor::new(&data[..])).ok().map(|h| h.seq);
+
+    let mut attempt = 0;
+    loop {
+        let result = transport
+            .send(&data)
+            .and_then(|_| transport.recv())
+            .and_then(|buf| decode_response(&buf))
+            .and_then(|(header, body)| {
+                if let Some(seq) = expected_seq {
+                    if header.seq != seq {
+                        bail!("wrong sequence number: expected {}, got {}", seq, header.seq);
+                    }
+                }
+                Ok((header, body))
+            });
+
+        match result {
+            Ok(response) => return Ok(response),
+            Err(e) => {
+                if attempt >= cli.retries {
+                    return Err(e);
+                }
+                attempt += 1;
+                warn!("transceive failed ({}), retry {}/{}", e, attempt, cli.retries);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::next_seq_id;
+    use super::{check_rc, decode_response, next_seq_id, read_frame, serial_frame};
+    use serde_cbor::Value;
     use std::collections::HashSet;
+    use std::io::Cursor;
+
+    // wrap a payload in the serial framing, as a device would emit it
+    fn frame(linelength: usize, payload: &[u8]) -> Vec<u8> {
+        serial_frame(linelength, payload).unwrap()
+    }
+
+    #[test]
+    fn read_frame_roundtrips_single_line() {
+        let payload = b"hello smp";
+        let framed = frame(128, payload);
+        let out = read_frame(&mut Cursor::new(framed)).unwrap();
+        assert_eq!(out, payload);
+    }
+
+    #[test]
+    fn read_frame_reassembles_unaligned_multi_line() {
+        // a long payload with a short line length forces continuation lines;
+        // linelength 9 yields 5 base64 chars per line, not a multiple of 4, so
+        // this only passes if the base64 text is concatenated before decoding
+        let payload: Vec<u8> = (0..64u8).collect();
+        let framed = frame(9, &payload);
+        let out = read_frame(&mut Cursor::new(framed)).unwrap();
+        assert_eq!(out, payload);
+    }
+
+    #[test]
+    fn read_frame_skips_leading_noise() {
+        let payload = b"resync me";
+        let mut input = b"boot banner text\r\n".to_vec();
+        input.extend_from_slice(&frame(128, payload));
+        let out = read_frame(&mut Cursor::new(input)).unwrap();
+        assert_eq!(out, payload);
+    }
+
+    #[test]
+    fn read_frame_gives_up_after_budget() {
+        // no start marker at all: bail instead of reading forever
+        let input = vec![0u8; super::START_MARKER_BUDGET + 100];
+        assert!(read_frame(&mut Cursor::new(input)).is_err());
+    }
+
+    #[test]
+    fn read_frame_rejects_corrupt_checksum() {
+        let mut framed = frame(128, b"tamper");
+        // flip a byte in the middle of the base64 payload
+        let mid = framed.len() / 2;
+        framed[mid] ^= 0x01;
+        assert!(read_frame(&mut Cursor::new(framed)).is_err());
+    }
+
+    #[test]
+    fn decode_response_rejects_short_buffer() {
+        assert!(decode_response(&[0u8; 4]).is_err());
+    }
+
+    #[test]
+    fn decode_response_rejects_garbage_body() {
+        // a full 8-byte header but no valid CBOR body must error, not panic
+        assert!(decode_response(&[0u8; 8]).is_err());
+    }
+
+    fn map(pairs: Vec<(&str, Value)>) -> Value {
+        Value::Map(
+            pairs
+                .into_iter()
+                .map(|(k, v)| (Value::Text(k.into()), v))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn check_rc_accepts_zero_flat_rc() {
+        assert!(check_rc(&map(vec![("rc", Value::Integer(0))])).is_ok());
+    }
+
+    #[test]
+    fn check_rc_rejects_nonzero_flat_rc() {
+        assert!(check_rc(&map(vec![("rc", Value::Integer(8))])).is_err());
+    }
+
+    #[test]
+    fn check_rc_rejects_nonzero_err_map() {
+        let err = map(vec![("group", Value::Integer(1)), ("rc", Value::Integer(8))]);
+        assert!(check_rc(&map(vec![("err", err)])).is_err());
+    }
+
+    #[test]
+    fn check_rc_accepts_zero_err_map() {
+        let err = map(vec![("group", Value::Integer(0)), ("rc", Value::Integer(0))]);
+        assert!(check_rc(&map(vec![("err", err)])).is_ok());
+    }
+
+    #[test]
+    fn check_rc_accepts_err_map_without_rc() {
+        let err = map(vec![("group", Value::Integer(1))]);
+        assert!(check_rc(&map(vec![("err", err)])).is_ok());
+    }
 
     #[test]
     fn test_next_seq_id() {